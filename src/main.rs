@@ -2,12 +2,16 @@
 extern crate rocket;
 
 use auto_launch::*;
+use rocket::{Build, Rocket};
 use std::env;
+use std::io::Read;
+use std::process::exit;
 
 mod print;
 mod cors;
 
 use cors::Cors;
+use print::PrintInfo;
 
 #[get("/")]
 fn index() -> &'static str {
@@ -18,8 +22,8 @@ fn index() -> &'static str {
 #[options("/<_..>")]
 fn options() {}
 
-#[launch]
-fn rocket() -> _ {
+/// Build the configured Rocket instance, registering auto-launch on the way.
+fn build_rocket() -> Rocket<Build> {
     let exe_path = env::current_exe().expect("Failed to get current executable path");
 
     let auto = AutoLaunchBuilder::new()
@@ -47,7 +51,67 @@ fn rocket() -> _ {
                 options,
                 print::print_receipt,
                 print::print_receipt_info,
-                print::print_receipt_options
+                print::print_receipt_options,
+                print::printer_status,
+                print::list_printers_route
             ],
         )
 }
+
+/// Print a single job read from a file or stdin, then exit. Never starts the
+/// HTTP server. Writes the outcome to stderr and uses the exit code to signal
+/// success (0), a failed print (1), or bad arguments or an unreadable or
+/// invalid job (2) so the binary is usable from cron and shell scripts.
+fn run_cli(args: &[String]) -> ! {
+    let json = if let Some(pos) = args.iter().position(|a| a == "--file") {
+        let path = args.get(pos + 1).unwrap_or_else(|| {
+            eprintln!("print: --file requires a path");
+            exit(2);
+        });
+        std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("print: failed to read {}: {}", path, e);
+            exit(2);
+        })
+    } else if args.iter().any(|a| a == "--stdin") {
+        let mut buf = String::new();
+        std::io::stdin().read_to_string(&mut buf).unwrap_or_else(|e| {
+            eprintln!("print: failed to read stdin: {}", e);
+            exit(2);
+        });
+        buf
+    } else {
+        eprintln!("print: expected --file <path> or --stdin");
+        exit(2);
+    };
+
+    let info: PrintInfo = rocket::serde::json::serde_json::from_str(&json).unwrap_or_else(|e| {
+        eprintln!("print: invalid job JSON: {}", e);
+        exit(2);
+    });
+
+    match print::print_to_thermal_printer(info.vid, info.pid, info.lines) {
+        Ok(()) => {
+            eprintln!("Printed successfully.");
+            exit(0);
+        }
+        Err(e) => {
+            eprintln!("Printing failed: {}", e);
+            exit(1);
+        }
+    }
+}
+
+#[rocket::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // Headless CLI mode: `print --file job.json` / `print --stdin`.
+    if args.get(1).map(String::as_str) == Some("print") {
+        run_cli(&args[2..]);
+    }
+
+    if let Err(e) = build_rocket().launch().await {
+        eprintln!("Rocket failed to launch: {}", e);
+        exit(1);
+    }
+}
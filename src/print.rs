@@ -1,12 +1,140 @@
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
 use rocket::serde::json::Json;
-use rocket::{get, post};
+use rocket::{get, post, Request};
 use rusb::{Context, UsbContext};
-use serde::Deserialize;
-use std::error::Error as StdError;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
 use std::time::Duration;
-use qrcode::QrCode;
+use qrcode::{EcLevel, QrCode};
+use qrcode::types::QrError;
 use image::{Luma, DynamicImage};
 
+/// Error raised while talking to a thermal printer.
+///
+/// Modelled after the `brother-ql-rs` driver's `Error` enum: a handful of
+/// explicit variants plus a wrapped `rusb::Error` for anything that happens on
+/// the USB bus. `Display` renders a human-readable message; the `Responder`
+/// impl below turns each variant into the appropriate HTTP status.
+#[derive(Debug)]
+pub enum PrintError {
+    /// No device with the requested VID/PID was connected.
+    DeviceNotFound,
+    /// The device was found but lacked a bulk endpoint the operation needs:
+    /// bulk-OUT for printing, and bulk-OUT plus bulk-IN for a status query.
+    NoBulkEndpoint,
+    /// A USB transfer, claim, or driver-detach call failed.
+    Usb(rusb::Error),
+    /// The payload could not be encoded as a QR code.
+    Qr(QrError),
+    /// A pre-print status check reported the printer is out of paper.
+    PaperOut,
+    /// A supplied image could not be base64-decoded or parsed.
+    Decode(String),
+}
+
+impl std::fmt::Display for PrintError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrintError::DeviceNotFound => write!(f, "Thermal printer not found"),
+            PrintError::NoBulkEndpoint => write!(f, "No suitable bulk endpoint found"),
+            PrintError::Usb(e) => write!(f, "USB error: {}", e),
+            PrintError::Qr(e) => write!(f, "QR generation failed: {}", e),
+            PrintError::PaperOut => write!(f, "Printer is out of paper"),
+            PrintError::Decode(e) => write!(f, "Image decode failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PrintError {}
+
+impl From<rusb::Error> for PrintError {
+    fn from(e: rusb::Error) -> Self {
+        PrintError::Usb(e)
+    }
+}
+
+impl From<QrError> for PrintError {
+    fn from(e: QrError) -> Self {
+        PrintError::Qr(e)
+    }
+}
+
+impl PrintError {
+    /// HTTP status mapped to each failure class.
+    fn status(&self) -> Status {
+        match self {
+            PrintError::DeviceNotFound => Status::NotFound,
+            PrintError::NoBulkEndpoint => Status::BadGateway,
+            PrintError::Usb(_) => Status::InternalServerError,
+            PrintError::Qr(_) => Status::BadRequest,
+            PrintError::PaperOut => Status::ServiceUnavailable,
+            PrintError::Decode(_) => Status::BadRequest,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for PrintError {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        // Escape the message for embedding in a JSON string literal rather than
+        // pulling in a bare `serde_json::` path (not a baseline dependency).
+        let escaped = self.to_string().replace('\\', "\\\\").replace('"', "\\\"");
+        let body = format!("{{\"error\":\"{}\"}}", escaped);
+        Response::build()
+            .status(self.status())
+            .header(ContentType::JSON)
+            .sized_body(body.len(), Cursor::new(body))
+            .ok()
+    }
+}
+
+/// Result body returned to a client on a successful print.
+#[derive(Serialize)]
+pub struct PrintResult {
+    pub user: String,
+}
+
+/// Decoded printer status, read back over the bulk-IN endpoint.
+#[derive(Serialize)]
+pub struct PrinterStatus {
+    pub online: bool,
+    pub paper_present: bool,
+    pub cover_open: bool,
+    pub error: bool,
+}
+
+/// QR error-correction level, mirroring the four levels the keyfork `qrcode`
+/// utility exposes. Deserializes from the single characters `L`/`M`/`Q`/`H`.
+#[derive(Deserialize, Clone, Copy)]
+pub enum QrEc {
+    L,
+    M,
+    Q,
+    H,
+}
+
+impl QrEc {
+    /// The matching `qrcode` crate error-correction level.
+    fn ec_level(self) -> EcLevel {
+        match self {
+            QrEc::L => EcLevel::L,
+            QrEc::M => EcLevel::M,
+            QrEc::Q => EcLevel::Q,
+            QrEc::H => EcLevel::H,
+        }
+    }
+
+    /// The `GS ( k` error-correction selector byte (48..=51 for L/M/Q/H).
+    fn escpos_byte(self) -> u8 {
+        match self {
+            QrEc::L => 48,
+            QrEc::M => 49,
+            QrEc::Q => 50,
+            QrEc::H => 51,
+        }
+    }
+}
+
 /// Line: one printable line of text with formatting & optional QR
 #[derive(Deserialize, Clone)]
 pub struct Line {
@@ -15,6 +143,24 @@ pub struct Line {
     pub bold: bool,
     pub double_size: bool,
     pub qr: Option<String>,
+    /// A base64-encoded image (PNG/JPEG/…) to print as an ESC/POS raster
+    /// bitmap, e.g. a store logo or a scanned label.
+    #[serde(default)]
+    pub image_base64: Option<String>,
+    /// Apply Floyd–Steinberg dithering when rasterizing `image_base64` so
+    /// photos stay legible instead of collapsing to a solid block.
+    #[serde(default)]
+    pub dither: bool,
+    /// Luma cutoff (0..=255) below which a pixel prints black; defaults to 128.
+    #[serde(default)]
+    pub threshold: Option<u8>,
+    /// Error-correction level for `qr`; defaults to `M` when omitted.
+    #[serde(default)]
+    pub qr_ec: Option<QrEc>,
+    /// Emit the printer's native `GS ( k` QR commands instead of a rasterized
+    /// `GS v 0` bitmap.
+    #[serde(default)]
+    pub qr_native: bool,
 }
 
 /// PrintInfo: full print job
@@ -24,21 +170,76 @@ pub struct PrintInfo {
     pub pid: u16,
     pub vid: u16,
     pub lines: Vec<Line>,
+    /// When true, query the printer's status before printing and fail fast
+    /// if it reports no paper.
+    #[serde(default)]
+    pub check_status: bool,
 }
 
 /// Generate a QR code as a bitmap image
-fn generate_qr(data: &str) -> DynamicImage {
-    let code = QrCode::new(data).unwrap();
+fn generate_qr(data: &str, ec: EcLevel) -> Result<DynamicImage, PrintError> {
+    let code = QrCode::with_error_correction_level(data, ec)?;
     let img = code.render::<Luma<u8>>().build();
-    DynamicImage::ImageLuma8(img)
+    Ok(DynamicImage::ImageLuma8(img))
 }
 
-/// Convert QR image to ESC/POS bytes
-fn qr_to_escpos(image: DynamicImage) -> Vec<u8> {
+/// Default module size (dot width) for native QR rendering, 1..=16.
+const QR_MODULE_SIZE: u8 = 6;
+
+/// Printable width of the thermal head in dots (58 mm head).
+const PRINTER_WIDTH_DOTS: u32 = 384;
+
+/// Encode a QR code as the printer's native `GS ( k` command sequence.
+fn qr_to_native(data: &str, ec: QrEc) -> Vec<u8> {
     let mut bytes = Vec::new();
 
-    let qr_resized = image.resize(200, 200, image::imageops::FilterType::Nearest);
-    let gray = qr_resized.to_luma8();
+    // Select model 2.
+    bytes.extend_from_slice(&[0x1D, 0x28, 0x6B, 0x04, 0x00, 0x31, 0x41, 0x32, 0x00]);
+    // Set module size.
+    bytes.extend_from_slice(&[0x1D, 0x28, 0x6B, 0x03, 0x00, 0x31, 0x43, QR_MODULE_SIZE]);
+    // Set error correction level.
+    bytes.extend_from_slice(&[0x1D, 0x28, 0x6B, 0x03, 0x00, 0x31, 0x45, ec.escpos_byte()]);
+
+    // Store the payload in the symbol storage area.
+    let store_len = data.len() + 3;
+    bytes.extend_from_slice(&[
+        0x1D,
+        0x28,
+        0x6B,
+        (store_len & 0xFF) as u8,
+        (store_len >> 8) as u8,
+        0x31,
+        0x50,
+        0x30,
+    ]);
+    bytes.extend_from_slice(data.as_bytes());
+
+    // Print the stored symbol.
+    bytes.extend_from_slice(&[0x1D, 0x28, 0x6B, 0x03, 0x00, 0x31, 0x51, 0x30]);
+
+    bytes
+}
+
+/// Convert an arbitrary image to a `GS v 0` raster bitmap.
+///
+/// The image is downscaled to at most `max_width` dots (preserving aspect
+/// ratio), converted to grayscale, and reduced to 1-bpp. When `dither` is set
+/// the grayscale is Floyd–Steinberg dithered first so photographs reproduce as
+/// legible halftones; otherwise a hard `threshold` cutoff is used. Both QR
+/// codes and user-supplied logos go through this emitter.
+fn raster_to_escpos(image: DynamicImage, max_width: u32, dither: bool, threshold: u8) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    let source = if image.width() > max_width {
+        image.resize(max_width, u32::MAX, image::imageops::FilterType::Lanczos3)
+    } else {
+        image
+    };
+    let mut gray = source.to_luma8();
+
+    if dither {
+        floyd_steinberg(&mut gray, threshold);
+    }
 
     bytes.extend_from_slice(b"\x1D\x76\x30\x00"); // GS v 0
 
@@ -57,7 +258,7 @@ fn qr_to_escpos(image: DynamicImage) -> Vec<u8> {
                 let x = x_byte * 8 + bit;
                 if x < gray.width() as u16 {
                     let pixel = gray.get_pixel(x as u32, y);
-                    if pixel[0] < 128 {
+                    if pixel[0] < threshold {
                         b |= 1 << (7 - bit);
                     }
                 }
@@ -69,8 +270,77 @@ fn qr_to_escpos(image: DynamicImage) -> Vec<u8> {
     bytes
 }
 
+/// In-place Floyd–Steinberg dithering to pure black/white around `threshold`.
+fn floyd_steinberg(img: &mut image::GrayImage, threshold: u8) {
+    let (w, h) = (img.width() as i32, img.height() as i32);
+    for y in 0..h {
+        for x in 0..w {
+            let old = img.get_pixel(x as u32, y as u32)[0] as i32;
+            let new = if old < threshold as i32 { 0 } else { 255 };
+            img.put_pixel(x as u32, y as u32, Luma([new as u8]));
+            let err = old - new;
+            let mut spread = |dx: i32, dy: i32, factor: i32| {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx >= 0 && nx < w && ny >= 0 && ny < h {
+                    let p = img.get_pixel(nx as u32, ny as u32)[0] as i32;
+                    let v = (p + err * factor / 16).clamp(0, 255);
+                    img.put_pixel(nx as u32, ny as u32, Luma([v as u8]));
+                }
+            };
+            spread(1, 0, 7);
+            spread(-1, 1, 3);
+            spread(0, 1, 5);
+            spread(1, 1, 1);
+        }
+    }
+}
+
+/// Decode a standard base64 string into raw bytes.
+///
+/// Implemented inline rather than pulling in the `base64` crate (not a
+/// baseline dependency). Accepts optional `=` padding, skips ASCII whitespace,
+/// and rejects any other stray character.
+fn base64_decode(encoded: &str) -> Result<Vec<u8>, PrintError> {
+    fn value(c: u8) -> Result<u32, PrintError> {
+        match c {
+            b'A'..=b'Z' => Ok((c - b'A') as u32),
+            b'a'..=b'z' => Ok((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Ok((c - b'0' + 52) as u32),
+            b'+' => Ok(62),
+            b'/' => Ok(63),
+            _ => Err(PrintError::Decode("invalid base64 character".to_string())),
+        }
+    }
+
+    let bytes: Vec<u8> = encoded.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let bytes = bytes
+        .strip_suffix(b"==")
+        .or_else(|| bytes.strip_suffix(b"="))
+        .unwrap_or(bytes.as_slice());
+
+    let mut out = Vec::with_capacity(bytes.len() * 3 / 4);
+    for chunk in bytes.chunks(4) {
+        let mut acc = 0u32;
+        for &c in chunk {
+            acc = (acc << 6) | value(c)?;
+        }
+        acc <<= 6 * (4 - chunk.len());
+        for i in 0..chunk.len() - 1 {
+            out.push((acc >> (16 - i * 8)) as u8);
+        }
+    }
+
+    Ok(out)
+}
+
+/// Decode a base64-encoded image into a [`DynamicImage`].
+fn decode_image(encoded: &str) -> Result<DynamicImage, PrintError> {
+    let raw = base64_decode(encoded)?;
+    image::load_from_memory(&raw).map_err(|e| PrintError::Decode(e.to_string()))
+}
+
 /// Compose ESC/POS bytes from lines
-fn compose_print_data(lines: Vec<Line>) -> Vec<u8> {
+fn compose_print_data(lines: Vec<Line>) -> Result<Vec<u8>, PrintError> {
     let mut data = Vec::new();
 
     data.extend_from_slice(b"\x1B\x40"); // Initialize printer
@@ -105,9 +375,25 @@ fn compose_print_data(lines: Vec<Line>) -> Vec<u8> {
 
         // QR
         if let Some(qr_content) = &line.qr {
-            let qr_img = generate_qr(qr_content);
-            let qr_bytes = qr_to_escpos(qr_img);
-            data.extend_from_slice(&qr_bytes);
+            let ec = line.qr_ec.unwrap_or(QrEc::M);
+            if line.qr_native {
+                data.extend_from_slice(&qr_to_native(qr_content, ec));
+            } else {
+                // Scale to 200×200 with a nearest-neighbour filter first: a QR
+                // is hard-edged, so Lanczos ringing (used by the generalized
+                // helper for photos) would blur the modules and hurt scans.
+                let qr_img = generate_qr(qr_content, ec.ec_level())?
+                    .resize(200, 200, image::imageops::FilterType::Nearest);
+                data.extend_from_slice(&raster_to_escpos(qr_img, 200, false, 128));
+            }
+            data.push(b'\n');
+        }
+
+        // Image
+        if let Some(encoded) = &line.image_base64 {
+            let img = decode_image(encoded)?;
+            let threshold = line.threshold.unwrap_or(128);
+            data.extend_from_slice(&raster_to_escpos(img, PRINTER_WIDTH_DOTS, line.dither, threshold));
             data.push(b'\n');
         }
     }
@@ -121,11 +407,11 @@ fn compose_print_data(lines: Vec<Line>) -> Vec<u8> {
     data.extend_from_slice(b"\x1B\x64\x03");
     data.extend_from_slice(b"\x1D\x56\x00");
 
-    data
+    Ok(data)
 }
 
 /// Send data to the printer
-fn print_to_thermal_printer(vid: u16, pid: u16, lines: Vec<Line>) -> Result<(), Box<dyn StdError>> {
+pub fn print_to_thermal_printer(vid: u16, pid: u16, lines: Vec<Line>) -> Result<(), PrintError> {
     let context = Context::new()?;
 
     for device in context.devices()?.iter() {
@@ -162,7 +448,7 @@ fn print_to_thermal_printer(vid: u16, pid: u16, lines: Vec<Line>) -> Result<(),
             if let Some(endpoint) = bulk_out_endpoint {
                 println!("Using bulk out endpoint: 0x{:02x}", endpoint);
 
-                let print_data = compose_print_data(lines);
+                let print_data = compose_print_data(lines)?;
 
                 let timeout = Duration::from_secs(5);
                 let bytes_written = handle.write_bulk(endpoint, &print_data, timeout)?;
@@ -173,33 +459,182 @@ fn print_to_thermal_printer(vid: u16, pid: u16, lines: Vec<Line>) -> Result<(),
 
                 return Ok(());
             } else {
-                return Err("No suitable bulk out endpoint found".into());
+                return Err(PrintError::NoBulkEndpoint);
             }
         }
     }
 
-    Err("Thermal printer not found".into())
+    Err(PrintError::DeviceNotFound)
 }
 
-#[get("/print")]
-pub fn print_receipt() -> String {
-    match print_to_thermal_printer(0x0FE6, 0x811E, Vec::new()) {
-        Ok(_) => println!("Printed successfully."),
-        Err(e) => eprintln!("Printing failed: {}", e),
+/// Query the printer's real-time status over the bulk-IN endpoint.
+///
+/// Issues the ESC/POS real-time status transmission command `DLE EOT n`
+/// (`0x10 0x04 n`) three times — `n=1` for the printer status, `n=2` for the
+/// offline cause, and `n=4` for the paper sensor — and decodes the one-byte
+/// response of each into a [`PrinterStatus`].
+fn query_status(vid: u16, pid: u16) -> Result<PrinterStatus, PrintError> {
+    let context = Context::new()?;
+
+    for device in context.devices()?.iter() {
+        let desc = device.device_descriptor()?;
+        if desc.vendor_id() == vid && desc.product_id() == pid {
+            let handle = device.open()?;
+
+            let interface_number = 0;
+            if handle.kernel_driver_active(interface_number)? {
+                handle.detach_kernel_driver(interface_number)?;
+            }
+            handle.claim_interface(interface_number)?;
+
+            let config_desc = device.active_config_descriptor()?;
+            let mut bulk_out_endpoint = None;
+            let mut bulk_in_endpoint = None;
+
+            for interface in config_desc.interfaces() {
+                for interface_desc in interface.descriptors() {
+                    for endpoint_desc in interface_desc.endpoint_descriptors() {
+                        if endpoint_desc.transfer_type() != rusb::TransferType::Bulk {
+                            continue;
+                        }
+                        match endpoint_desc.direction() {
+                            rusb::Direction::Out => bulk_out_endpoint = Some(endpoint_desc.address()),
+                            rusb::Direction::In => bulk_in_endpoint = Some(endpoint_desc.address()),
+                        }
+                    }
+                }
+            }
+
+            let out_ep = bulk_out_endpoint.ok_or(PrintError::NoBulkEndpoint)?;
+            let in_ep = bulk_in_endpoint.ok_or(PrintError::NoBulkEndpoint)?;
+            let timeout = Duration::from_millis(500);
+
+            // Read a single status byte for the given `DLE EOT n` selector.
+            let transmit = |n: u8| -> Result<u8, PrintError> {
+                handle.write_bulk(out_ep, &[0x10, 0x04, n], timeout)?;
+                let mut buf = [0u8; 1];
+                handle.read_bulk(in_ep, &mut buf, timeout)?;
+                Ok(buf[0])
+            };
+
+            let printer = transmit(1)?;
+            let offline = transmit(2)?;
+            let paper = transmit(4)?;
+
+            handle.release_interface(interface_number)?;
+
+            return Ok(PrinterStatus {
+                online: printer & 0x08 == 0,
+                paper_present: paper & 0x60 == 0,
+                cover_open: offline & 0x04 != 0,
+                error: offline & 0x40 != 0,
+            });
+        }
     }
-    format!("User: oscar")
+
+    Err(PrintError::DeviceNotFound)
+}
+
+/// A USB thermal printer discovered on the bus.
+#[derive(Serialize)]
+pub struct PrinterInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub bulk_out_endpoint: u8,
+}
+
+/// Enumerate connected USB printers.
+///
+/// Reuses the same `rusb::Context` device iteration as
+/// [`print_to_thermal_printer`], keeping only devices that expose a bulk-OUT
+/// endpoint on a printer-class interface (USB class `0x07`). The manufacturer
+/// and product strings are read through an open handle when permissions allow;
+/// they are left `None` otherwise so enumeration still works unprivileged.
+fn list_printers() -> Result<Vec<PrinterInfo>, PrintError> {
+    let context = Context::new()?;
+    let mut printers = Vec::new();
+
+    for device in context.devices()?.iter() {
+        let desc = device.device_descriptor()?;
+        let config_desc = match device.active_config_descriptor() {
+            Ok(cd) => cd,
+            Err(_) => continue,
+        };
+
+        let mut bulk_out_endpoint = None;
+        for interface in config_desc.interfaces() {
+            for interface_desc in interface.descriptors() {
+                if interface_desc.class_code() != 0x07 {
+                    continue;
+                }
+                for endpoint_desc in interface_desc.endpoint_descriptors() {
+                    if endpoint_desc.direction() == rusb::Direction::Out
+                        && endpoint_desc.transfer_type() == rusb::TransferType::Bulk
+                    {
+                        bulk_out_endpoint = Some(endpoint_desc.address());
+                    }
+                }
+            }
+        }
+
+        let Some(endpoint) = bulk_out_endpoint else {
+            continue;
+        };
+
+        let (manufacturer, product) = match device.open() {
+            Ok(handle) => (
+                handle.read_manufacturer_string_ascii(&desc).ok(),
+                handle.read_product_string_ascii(&desc).ok(),
+            ),
+            Err(_) => (None, None),
+        };
+
+        printers.push(PrinterInfo {
+            vid: desc.vendor_id(),
+            pid: desc.product_id(),
+            manufacturer,
+            product,
+            bulk_out_endpoint: endpoint,
+        });
+    }
+
+    Ok(printers)
+}
+
+#[get("/printers")]
+pub fn list_printers_route() -> Result<Json<Vec<PrinterInfo>>, PrintError> {
+    Ok(Json(list_printers()?))
+}
+
+#[get("/status?<vid>&<pid>")]
+pub fn printer_status(vid: u16, pid: u16) -> Result<Json<PrinterStatus>, PrintError> {
+    Ok(Json(query_status(vid, pid)?))
+}
+
+#[get("/print")]
+pub fn print_receipt() -> Result<Json<PrintResult>, PrintError> {
+    print_to_thermal_printer(0x0FE6, 0x811E, Vec::new())?;
+    println!("Printed successfully.");
+    Ok(Json(PrintResult { user: "oscar".to_string() }))
 }
 
 #[post("/print", format = "json", data = "<print_info>")]
-pub fn print_receipt_info(print_info: Json<PrintInfo>) -> String {
+pub fn print_receipt_info(print_info: Json<PrintInfo>) -> Result<Json<PrintResult>, PrintError> {
     println!(
         "Received VID: {}, PID: {}, Name: {}",
         print_info.vid, print_info.pid, print_info.name
     );
 
-    match print_to_thermal_printer(print_info.vid, print_info.pid, print_info.lines.clone()) {
-        Ok(_) => println!("Printed successfully."),
-        Err(e) => eprintln!("Printing failed: {}", e),
+    if print_info.check_status {
+        let status = query_status(print_info.vid, print_info.pid)?;
+        if !status.paper_present {
+            return Err(PrintError::PaperOut);
+        }
     }
-    format!("User: {}", print_info.name)
+
+    print_to_thermal_printer(print_info.vid, print_info.pid, print_info.lines.clone())?;
+    println!("Printed successfully.");
+    Ok(Json(PrintResult { user: print_info.name.clone() }))
 }